@@ -14,6 +14,7 @@ fn main() {
     if let Some(dir) = ffmpeg_dir {
         println!("cargo:rustc-link-search=native={}/lib", dir.display());
         println!("cargo:rustc-link-lib=avcodec");
+        println!("cargo:rustc-link-lib=avformat");
         println!("cargo:rustc-link-lib=avutil");
         println!("cargo:rustc-link-lib=swscale");
         
@@ -2,116 +2,365 @@
 
 use anyhow::{Context, Result, bail};
 use std::ptr;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
 
-use crate::ipc::{FrameData, PacketData, PixelFormat};
+use crate::ipc::{ColorMatrix, ControlUpdate, FrameData, PacketData, PixelFormat};
+use crate::recorder::StreamRecorder;
 
-// FFmpeg bindings (由 build.rs 生成)
+// FFmpeg bindings (由 build.rs 生成)。`recorder` 模块也会用到这里的类型，
+// 因此导出为 pub(crate) 而不是各自生成一份 (避免重复生成的类型互不兼容)
 #[allow(non_upper_case_globals)]
 #[allow(non_camel_case_types)]
 #[allow(non_snake_case)]
 #[allow(dead_code)]
-mod ffi {
+pub(crate) mod ffi {
     include!(concat!(env!("OUT_DIR"), "/ffmpeg_bindings.rs"));
 }
 
 /// 视频编码器
 pub struct VideoEncoder {
     codec_ctx: *mut ffi::AVCodecContext,
+    codec: *const ffi::AVCodec,
+    codec_id: ffi::AVCodecID,
+    hw_encoder: bool,
     frame: *mut ffi::AVFrame,
     sws_ctx: *mut ffi::SwsContext,
     width: u32,
     height: u32,
     frame_count: u64,
+    /// 驱动通过控制通道请求了强制 IDR，将在下一次 `encode_frame` 中消费
+    force_idr_pending: bool,
+    /// 待录制文件路径 (通过 CLI/环境变量开启)，首帧到达、编码器格式确定后才真正打开
+    record_path: Option<String>,
+    /// 可选的调试录制器，为 `None` 时不产生任何额外开销
+    recorder: Option<StreamRecorder>,
 }
 
 impl VideoEncoder {
-    pub fn new(width: u32, height: u32, codec_name: &str) -> Result<Self> {
+    pub fn new(
+        width: u32,
+        height: u32,
+        codec_name: &str,
+        record_path: Option<String>,
+    ) -> Result<Self> {
         unsafe {
-            // 查找编码器
-            let codec_id = match codec_name {
-                "h264" => ffi::AVCodecID_AV_CODEC_ID_H264,
-                "hevc" | "h265" => ffi::AVCodecID_AV_CODEC_ID_HEVC,
+            // 软件编码器始终可用，作为硬件编码器名称解析/打开失败时的后备
+            let sw_codec_id = match codec_name {
+                "hevc" | "h265" | "hevc_mf" | "hevc_amf" => ffi::AVCodecID_AV_CODEC_ID_HEVC,
                 _ => ffi::AVCodecID_AV_CODEC_ID_H264,
             };
-            
-            let codec = ffi::avcodec_find_encoder(codec_id);
+
+            // 按名称解析硬件编码器 (Media Foundation / AMF)，找不到则直接退回软件编码器
+            let mut codec: *const ffi::AVCodec = ptr::null();
+            let mut hw_encoder = false;
+            if matches!(codec_name, "h264_mf" | "hevc_mf" | "h264_amf" | "hevc_amf") {
+                let name = std::ffi::CString::new(codec_name).unwrap();
+                let hw_codec = ffi::avcodec_find_encoder_by_name(name.as_ptr());
+                if hw_codec.is_null() {
+                    info!(
+                        "Hardware encoder '{}' not found, falling back to software",
+                        codec_name
+                    );
+                } else {
+                    codec = hw_codec;
+                    hw_encoder = true;
+                }
+            }
             if codec.is_null() {
-                bail!("Could not find encoder for codec {:?}", codec_name);
+                codec = ffi::avcodec_find_encoder(sw_codec_id);
             }
-            
-            // 分配编码器上下文
-            let codec_ctx = ffi::avcodec_alloc_context3(codec);
-            if codec_ctx.is_null() {
-                bail!("Could not allocate codec context");
+            if codec.is_null() {
+                bail!("Could not find encoder for codec {:?}", codec_name);
             }
-            
-            // 配置编码器
-            (*codec_ctx).width = width as i32;
-            (*codec_ctx).height = height as i32;
-            (*codec_ctx).time_base = ffi::AVRational { num: 1, den: 1_000_000_000 }; // nanoseconds
-            (*codec_ctx).framerate = ffi::AVRational { num: 72, den: 1 };
-            (*codec_ctx).pix_fmt = ffi::AVPixelFormat_AV_PIX_FMT_YUV420P;
-            (*codec_ctx).gop_size = 0; // All intra
-            (*codec_ctx).max_b_frames = 0;
-            (*codec_ctx).bit_rate = 30_000_000; // 30 Mbps default
-            
-            // 设置低延迟选项
-            let mut opts: *mut ffi::AVDictionary = ptr::null_mut();
-            let preset = std::ffi::CString::new("preset").unwrap();
-            let ultrafast = std::ffi::CString::new("ultrafast").unwrap();
-            ffi::av_dict_set(&mut opts, preset.as_ptr(), ultrafast.as_ptr(), 0);
-            
-            let tune = std::ffi::CString::new("tune").unwrap();
-            let zerolatency = std::ffi::CString::new("zerolatency").unwrap();
-            ffi::av_dict_set(&mut opts, tune.as_ptr(), zerolatency.as_ptr(), 0);
-            
-            // 打开编码器
-            let ret = ffi::avcodec_open2(codec_ctx, codec, &mut opts);
+
+            let mut codec_ctx = Self::alloc_context(codec, width, height)?;
+            let mut opts = Self::rate_control_opts(hw_encoder);
+            let mut ret = ffi::avcodec_open2(codec_ctx, codec, &mut opts);
             ffi::av_dict_free(&mut opts);
-            
+
+            // 硬件编码器打开失败 (例如驱动不支持该分辨率)，退回软件编码器重试一次
+            if ret < 0 && hw_encoder {
+                info!(
+                    "Hardware encoder '{}' failed to open (error {}), falling back to software",
+                    codec_name, ret
+                );
+                ffi::avcodec_free_context(&mut codec_ctx);
+
+                codec = ffi::avcodec_find_encoder(sw_codec_id);
+                if codec.is_null() {
+                    bail!("Could not find software fallback encoder");
+                }
+                hw_encoder = false;
+
+                codec_ctx = Self::alloc_context(codec, width, height)?;
+                let mut opts = Self::rate_control_opts(hw_encoder);
+                ret = ffi::avcodec_open2(codec_ctx, codec, &mut opts);
+                ffi::av_dict_free(&mut opts);
+            }
+
             if ret < 0 {
-                ffi::avcodec_free_context(&mut (codec_ctx as *mut _));
+                ffi::avcodec_free_context(&mut codec_ctx);
                 bail!("Could not open codec: error {}", ret);
             }
-            
+
             // 分配帧
             let frame = ffi::av_frame_alloc();
             if frame.is_null() {
-                ffi::avcodec_free_context(&mut (codec_ctx as *mut _));
+                ffi::avcodec_free_context(&mut codec_ctx);
                 bail!("Could not allocate frame");
             }
-            
+
             (*frame).width = width as i32;
             (*frame).height = height as i32;
             (*frame).format = ffi::AVPixelFormat_AV_PIX_FMT_YUV420P as i32;
-            
+
             let ret = ffi::av_frame_get_buffer(frame, 0);
             if ret < 0 {
                 ffi::av_frame_free(&mut (frame as *mut _));
-                ffi::avcodec_free_context(&mut (codec_ctx as *mut _));
+                ffi::avcodec_free_context(&mut codec_ctx);
                 bail!("Could not allocate frame buffer: error {}", ret);
             }
-            
-            info!("VideoEncoder initialized: {}x{}, codec: {}", width, height, codec_name);
-            
+
+            info!(
+                "VideoEncoder initialized: {}x{}, codec: {} ({})",
+                width,
+                height,
+                codec_name,
+                if hw_encoder { "hardware" } else { "software" }
+            );
+
             Ok(Self {
                 codec_ctx,
+                codec,
+                codec_id: sw_codec_id,
+                hw_encoder,
                 frame,
                 sws_ctx: ptr::null_mut(),
                 width,
                 height,
                 frame_count: 0,
+                force_idr_pending: false,
+                record_path,
+                recorder: None,
             })
         }
     }
+
+    /// 应用驱动通过共享内存控制通道下发的运行时参数 (码率/帧率/强制 IDR)。
+    /// ALVR 为自适应码流会持续小幅调整码率，这条路径必须轻量：libx264/
+    /// libx265 会在每帧检查 `codec_ctx.bit_rate` 是否变化并据此触发内部
+    /// reconfig，MF/AMF 的私有码率选项也映射到同一个字段，所以纯码率变更
+    /// 直接写 `bit_rate` 即可，既不需要重开编码器，也不会丢参考帧/插入 IDR。
+    /// 帧率变更会改变 GOP 时间假设，仍然通过重开处理
+    pub fn apply_control_update(&mut self, update: &ControlUpdate) {
+        if update.target_bitrate_bps > 0 {
+            unsafe {
+                (*self.codec_ctx).bit_rate = update.target_bitrate_bps as i64;
+            }
+            debug!("Applied control update: bitrate={} bps", update.target_bitrate_bps);
+        }
+        if update.target_framerate > 0 {
+            if let Err(e) = unsafe { self.reopen_for_framerate(update.target_framerate) } {
+                warn!("Failed to apply framerate control update: {}", e);
+            } else {
+                debug!("Applied control update: framerate={} fps", update.target_framerate);
+            }
+        }
+        if update.force_idr {
+            self.force_idr_pending = true;
+        }
+    }
+
+    /// 重新打开编码器上下文以应用新的帧率，保留当前的像素格式/profile/码率/
+    /// 颜色信息。重开会丢弃编码器内部的参考帧状态，因此强制下一帧为 IDR
+    unsafe fn reopen_for_framerate(&mut self, framerate: u32) -> Result<()> {
+        let pix_fmt = (*self.codec_ctx).pix_fmt;
+        let profile = (*self.codec_ctx).profile;
+        let bit_rate = (*self.codec_ctx).bit_rate;
+        let color_primaries = (*self.codec_ctx).color_primaries;
+        let color_trc = (*self.codec_ctx).color_trc;
+        let colorspace = (*self.codec_ctx).colorspace;
+        let color_range = (*self.codec_ctx).color_range;
+
+        let codec_ctx = Self::alloc_context(self.codec, self.width, self.height)?;
+        (*codec_ctx).pix_fmt = pix_fmt;
+        (*codec_ctx).profile = profile;
+        (*codec_ctx).bit_rate = bit_rate;
+        (*codec_ctx).framerate = ffi::AVRational { num: framerate as i32, den: 1 };
+        (*codec_ctx).color_primaries = color_primaries;
+        (*codec_ctx).color_trc = color_trc;
+        (*codec_ctx).colorspace = colorspace;
+        (*codec_ctx).color_range = color_range;
+
+        let mut opts = Self::rate_control_opts(self.hw_encoder);
+        let ret = ffi::avcodec_open2(codec_ctx, self.codec, &mut opts);
+        ffi::av_dict_free(&mut opts);
+        if ret < 0 {
+            let mut codec_ctx = codec_ctx;
+            ffi::avcodec_free_context(&mut codec_ctx);
+            bail!("Could not reopen codec for framerate update: error {}", ret);
+        }
+
+        ffi::avcodec_free_context(&mut self.codec_ctx);
+        self.codec_ctx = codec_ctx;
+        self.force_idr_pending = true;
+        Ok(())
+    }
+
+    /// 根据输入像素格式选择编码器输出格式与 profile：HEVC 下的 P010 走 10-bit
+    /// Main10，其余情况保留原有的 8-bit YUV420P 路径
+    fn target_output_format(
+        codec_id: ffi::AVCodecID,
+        pixel_format: PixelFormat,
+    ) -> (ffi::AVPixelFormat, i32) {
+        if codec_id == ffi::AVCodecID_AV_CODEC_ID_HEVC && matches!(pixel_format, PixelFormat::P010)
+        {
+            (
+                ffi::AVPixelFormat_AV_PIX_FMT_YUV420P10LE,
+                ffi::FF_PROFILE_HEVC_MAIN_10,
+            )
+        } else {
+            (ffi::AVPixelFormat_AV_PIX_FMT_YUV420P, ffi::FF_PROFILE_UNKNOWN)
+        }
+    }
+
+    /// 首帧到达后，按实际输入像素格式与颜色矩阵重新打开编码器并重新分配帧
+    /// 缓冲区。颜色矩阵必须在这里写进 `codec_ctx` 再 `avcodec_open2`——
+    /// libx264/libx265/MF 的 VUI 颜色信令是在打开时从 `AVCodecContext`
+    /// 读取的，打开之后再改 `AVFrame` 上的字段对已写入码流的 VUI 没有任何
+    /// 影响。首帧之前编码器在 `new()` 里已经用未知颜色矩阵打开过一次，所以
+    /// 这里总是重开，即使目标像素格式与当前上下文相同也不提前返回
+    fn reconfigure_for_format(
+        &mut self,
+        pixel_format: PixelFormat,
+        color_matrix: ColorMatrix,
+    ) -> Result<()> {
+        unsafe {
+            let (target_fmt, profile) = Self::target_output_format(self.codec_id, pixel_format);
+
+            info!(
+                "Reopening encoder for first frame: pixel format {:?} (target {:?}), color matrix {:?}",
+                pixel_format, target_fmt, color_matrix
+            );
+
+            // 先分配并尝试打开新的上下文，成功后才释放旧的；这样失败时
+            // `self.codec_ctx` 仍指向原本打开的上下文，不会留下悬空/空指针
+            // 让之后的 `apply_control_update` (直接解引用 `self.codec_ctx`)
+            // 崩溃
+            let mut codec_ctx = Self::alloc_context(self.codec, self.width, self.height)?;
+            (*codec_ctx).pix_fmt = target_fmt;
+            (*codec_ctx).profile = profile;
+
+            let (color_primaries, color_trc, colorspace) = match color_matrix {
+                ColorMatrix::Bt709 => (
+                    ffi::AVColorPrimaries_AVCOL_PRI_BT709,
+                    ffi::AVColorTransferCharacteristic_AVCOL_TRC_BT709,
+                    ffi::AVColorSpace_AVCOL_SPC_BT709,
+                ),
+                ColorMatrix::Bt2020 => (
+                    ffi::AVColorPrimaries_AVCOL_PRI_BT2020,
+                    ffi::AVColorTransferCharacteristic_AVCOL_TRC_BT2020_10,
+                    ffi::AVColorSpace_AVCOL_SPC_BT2020_NCL,
+                ),
+            };
+            (*codec_ctx).color_primaries = color_primaries;
+            (*codec_ctx).color_trc = color_trc;
+            (*codec_ctx).colorspace = colorspace;
+            (*codec_ctx).color_range = ffi::AVColorRange_AVCOL_RANGE_MPEG;
+
+            let mut opts = Self::rate_control_opts(self.hw_encoder);
+            let ret = ffi::avcodec_open2(codec_ctx, self.codec, &mut opts);
+            ffi::av_dict_free(&mut opts);
+            if ret < 0 {
+                ffi::avcodec_free_context(&mut codec_ctx);
+                bail!("Could not reopen codec for pixel format {:?}: error {}", pixel_format, ret);
+            }
+            ffi::avcodec_free_context(&mut self.codec_ctx);
+            self.codec_ctx = codec_ctx;
+
+            ffi::av_frame_free(&mut self.frame);
+            let frame = ffi::av_frame_alloc();
+            if frame.is_null() {
+                bail!("Could not allocate frame");
+            }
+            (*frame).width = self.width as i32;
+            (*frame).height = self.height as i32;
+            (*frame).format = target_fmt as i32;
+
+            let ret = ffi::av_frame_get_buffer(frame, 0);
+            if ret < 0 {
+                ffi::av_frame_free(&mut (frame as *mut _));
+                bail!("Could not allocate frame buffer: error {}", ret);
+            }
+            self.frame = frame;
+
+            Ok(())
+        }
+    }
+
+    /// 分配并配置编码器上下文的通用参数
+    unsafe fn alloc_context(
+        codec: *const ffi::AVCodec,
+        width: u32,
+        height: u32,
+    ) -> Result<*mut ffi::AVCodecContext> {
+        let codec_ctx = ffi::avcodec_alloc_context3(codec);
+        if codec_ctx.is_null() {
+            bail!("Could not allocate codec context");
+        }
+
+        (*codec_ctx).width = width as i32;
+        (*codec_ctx).height = height as i32;
+        (*codec_ctx).time_base = ffi::AVRational { num: 1, den: 1_000_000_000 }; // nanoseconds
+        (*codec_ctx).framerate = ffi::AVRational { num: 72, den: 1 };
+        (*codec_ctx).pix_fmt = ffi::AVPixelFormat_AV_PIX_FMT_YUV420P;
+        (*codec_ctx).gop_size = 0; // All intra
+        (*codec_ctx).max_b_frames = 0;
+        (*codec_ctx).bit_rate = 30_000_000; // 30 Mbps default
+
+        Ok(codec_ctx)
+    }
+
+    /// 构造低延迟码率控制选项。硬件编码器 (MF/AMF) 使用 rc_mode/usage/low_latency，
+    /// 软件编码器 (libx264/libx265) 使用 preset/tune
+    unsafe fn rate_control_opts(hw_encoder: bool) -> *mut ffi::AVDictionary {
+        let mut opts: *mut ffi::AVDictionary = ptr::null_mut();
+
+        let set = |opts: &mut *mut ffi::AVDictionary, key: &str, value: &str| {
+            let key = std::ffi::CString::new(key).unwrap();
+            let value = std::ffi::CString::new(value).unwrap();
+            ffi::av_dict_set(opts, key.as_ptr(), value.as_ptr(), 0);
+        };
+
+        if hw_encoder {
+            set(&mut opts, "rc_mode", "cbr");
+            set(&mut opts, "usage", "lowest_latency");
+            set(&mut opts, "low_latency", "1");
+        } else {
+            set(&mut opts, "preset", "ultrafast");
+            set(&mut opts, "tune", "zerolatency");
+        }
+
+        opts
+    }
     
     /// 编码一帧
     pub fn encode_frame(&mut self, frame_data: &FrameData) -> Result<Vec<PacketData>> {
         unsafe {
-            // 确保 sws_ctx 已初始化
+            // 确保 sws_ctx 已初始化；首帧到达时根据实际输入格式决定编码器输出格式
             if self.sws_ctx.is_null() {
-                self.init_scaler(frame_data.pixel_format)?;
+                self.reconfigure_for_format(frame_data.pixel_format, frame_data.color_matrix)?;
+                self.init_scaler(frame_data.pixel_format, frame_data.color_matrix)?;
+
+                // 编码器格式已确定，此时再打开录制文件，codecpar 才能反映真实的输出格式。
+                // 这是个调试用的可选功能，打开失败 (例如路径无效) 不应该搭上这一帧的编码——
+                // 只记录告警并放弃录制，本函数其余部分照常执行
+                if let Some(path) = self.record_path.take() {
+                    match StreamRecorder::new(&path, self.codec_ctx) {
+                        Ok(recorder) => self.recorder = Some(recorder),
+                        Err(e) => warn!("Failed to start recording to '{}': {}", path, e),
+                    }
+                }
             }
             
             // 准备输入数据
@@ -131,11 +380,12 @@ impl VideoEncoder {
             
             // 设置帧属性
             (*self.frame).pts = frame_data.timestamp_ns as i64;
-            (*self.frame).pict_type = if frame_data.insert_idr {
+            (*self.frame).pict_type = if frame_data.insert_idr || self.force_idr_pending {
                 ffi::AVPictureType_AV_PICTURE_TYPE_I
             } else {
                 ffi::AVPictureType_AV_PICTURE_TYPE_NONE
             };
+            self.force_idr_pending = false;
             
             // 发送帧到编码器
             let ret = ffi::avcodec_send_frame(self.codec_ctx, self.frame);
@@ -157,13 +407,29 @@ impl VideoEncoder {
                     bail!("Error receiving packet: {}", ret);
                 }
                 
+                // 录制已启用时，按编码器实际时间基写入一份数据包拷贝用于调试
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.write_packet(packet);
+                }
+
                 // 复制数据包数据
                 let data = std::slice::from_raw_parts((*packet).data, (*packet).size as usize);
                 let is_idr = ((*packet).flags & ffi::AV_PKT_FLAG_KEY as i32) != 0;
-                
+
+                // MF/AMF 等硬件编码器常常不像 libx264 那样推导 DTS，直接给
+                // AV_NOPTS_VALUE (i64::MIN) 或负值；原样 `as u64` 会变成一个
+                // 巨大的垃圾值，这里退回 PTS (无 B 帧/零延迟场景下 DTS==PTS)
+                let dts = (*packet).dts;
+                let dts_ns = if dts == ffi::AV_NOPTS_VALUE || dts < 0 {
+                    (*packet).pts as u64
+                } else {
+                    dts as u64
+                };
+
                 packets.push(PacketData {
                     data: data.to_vec(),
                     timestamp_ns: (*packet).pts as u64,
+                    dts_ns,
                     is_idr,
                 });
                 
@@ -182,42 +448,86 @@ impl VideoEncoder {
     }
     
     /// 初始化颜色空间转换器
-    fn init_scaler(&mut self, pixel_format: PixelFormat) -> Result<()> {
+    fn init_scaler(&mut self, pixel_format: PixelFormat, color_matrix: ColorMatrix) -> Result<()> {
         unsafe {
             let src_format = match pixel_format {
                 PixelFormat::Rgba => ffi::AVPixelFormat_AV_PIX_FMT_RGBA,
                 PixelFormat::Nv12 => ffi::AVPixelFormat_AV_PIX_FMT_NV12,
                 PixelFormat::P010 => ffi::AVPixelFormat_AV_PIX_FMT_P010,
             };
-            
+
+            // 目标格式取自已打开的编码器上下文，保证 sws_scale 的输出与
+            // encode_frame 中送入编码器的帧格式一致 (8-bit YUV420P 或 10-bit P010 输入对应的 YUV420P10LE)
+            let dst_format = (*self.codec_ctx).pix_fmt;
+
             self.sws_ctx = ffi::sws_getContext(
                 self.width as i32,
                 self.height as i32,
                 src_format,
                 self.width as i32,
                 self.height as i32,
-                ffi::AVPixelFormat_AV_PIX_FMT_YUV420P,
+                dst_format,
                 ffi::SWS_BILINEAR as i32,
                 ptr::null_mut(),
                 ptr::null_mut(),
                 ptr::null_mut(),
             );
-            
+
             if self.sws_ctx.is_null() {
                 bail!("Could not initialize sws context");
             }
-            
-            info!("Scaler initialized for pixel format {:?}", pixel_format);
+
+            // 压缩器默认按 BT.601 有限范围处理，VR 合成器输出的是全范围 RGBA，
+            // 不设置会导致头显里画面发灰、偏色
+            let dst_csp = match color_matrix {
+                ColorMatrix::Bt709 => ffi::SWS_CS_ITU709,
+                ColorMatrix::Bt2020 => ffi::SWS_CS_BT2020,
+            };
+
+            let mut inv_table: *mut i32 = ptr::null_mut();
+            let mut table: *mut i32 = ptr::null_mut();
+            let mut src_range = 0;
+            let mut dst_range = 0;
+            let mut brightness = 0;
+            let mut contrast = 0;
+            let mut saturation = 0;
+            ffi::sws_getColorspaceDetails(
+                self.sws_ctx,
+                &mut inv_table,
+                &mut src_range,
+                &mut table,
+                &mut dst_range,
+                &mut brightness,
+                &mut contrast,
+                &mut saturation,
+            );
+
+            let coeffs = ffi::sws_getCoefficients(dst_csp as i32);
+            let ret = ffi::sws_setColorspaceDetails(
+                self.sws_ctx,
+                coeffs,
+                1, // srcRange: 合成器输出为全范围 RGBA
+                coeffs,
+                0, // dstRange: H.264/HEVC 编码器按有限范围写入 VUI
+                brightness,
+                contrast,
+                saturation,
+            );
+            if ret < 0 {
+                bail!("Could not set scaler colorspace details: error {}", ret);
+            }
+
+            // VUI 颜色信令已经在 `reconfigure_for_format` 里写进 `codec_ctx`
+            // 并随 `avcodec_open2` 生效，这里不需要再对 `AVFrame` 设置一遍
+
+            info!(
+                "Scaler initialized for pixel format {:?}, color matrix {:?}",
+                pixel_format, color_matrix
+            );
             Ok(())
         }
     }
     
-    /// 更新比特率
-    pub fn set_bitrate(&mut self, bitrate_bps: u64) {
-        unsafe {
-            (*self.codec_ctx).bit_rate = bitrate_bps as i64;
-        }
-    }
 }
 
 impl Drop for VideoEncoder {
@@ -4,6 +4,7 @@ use anyhow::{Context, Result, bail};
 use serde::{Deserialize, Serialize};
 use std::ffi::c_void;
 use std::ptr;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
 use windows::core::PCWSTR;
 use windows::Win32::Foundation::{CloseHandle, HANDLE, WAIT_OBJECT_0};
 use windows::Win32::System::Memory::{
@@ -21,17 +22,56 @@ const FRAME_BUFFER_SIZE: usize = 4096 * 2160 * 4;
 /// 数据包缓冲区大小 (编码后数据通常更小)
 const PACKET_BUFFER_SIZE: usize = 4 * 1024 * 1024; // 4MB
 
-/// 共享内存布局
+/// 三缓冲槽位数量：生产者写入一个槽位的同时，消费者可以安全读取另一个，
+/// 第三个则给偶发的"读写撞到同一槽位"的重试留出余地
+pub const SLOT_COUNT: usize = 3;
+
+/// 槽位状态。生产者挑选待写入槽位时跳过 `Reading` 中的槽位，消费者读取前
+/// 把槽位置为 `Reading` 防止被生产者选中复用；数据撕裂仍靠 sequence 号的
+/// 读后复核检测 (状态机只避免"读的时候被换底"，不替代 seqlock)
+mod slot_state {
+    pub const EMPTY: u32 = 0;
+    pub const WRITING: u32 = 1;
+    pub const READY: u32 = 2;
+    pub const READING: u32 = 3;
+}
+
+/// 单个帧槽位：元数据 + 序号 + 状态 + 数据缓冲区
+#[repr(C)]
+pub struct FrameSlot {
+    pub header: FrameHeader,
+    /// 单调递增的发布序号，每次生产者重新写入该槽位时递增
+    pub sequence: AtomicU64,
+    pub state: AtomicU32,
+    _padding: [u8; 4],
+    pub data: [u8; FRAME_BUFFER_SIZE],
+}
+
+/// 单个数据包槽位，结构与 `FrameSlot` 对称
+#[repr(C)]
+pub struct PacketSlot {
+    pub header: PacketHeader,
+    pub sequence: AtomicU64,
+    pub state: AtomicU32,
+    _padding: [u8; 4],
+    pub data: [u8; PACKET_BUFFER_SIZE],
+}
+
+/// 共享内存布局。帧/数据包各使用 `SLOT_COUNT` 个槽位的环形三缓冲，生产者总能
+/// 找到一个当前没有消费者在读的槽位写入，消费者也不会在拷贝过程中被写覆盖
+/// 阻塞——最多整份重读一次
 #[repr(C)]
 pub struct SharedMemoryLayout {
-    /// 帧元数据
-    pub frame_header: FrameHeader,
-    /// 数据包元数据
-    pub packet_header: PacketHeader,
-    /// 帧数据缓冲区
-    pub frame_buffer: [u8; FRAME_BUFFER_SIZE],
-    /// 数据包缓冲区
-    pub packet_buffer: [u8; PACKET_BUFFER_SIZE],
+    /// 运行时控制参数 (码率/帧率/强制 IDR)
+    pub control_header: ControlHeader,
+    /// 帧槽位
+    pub frame_slots: [FrameSlot; SLOT_COUNT],
+    /// 生产者发布的最新帧槽位下标，写入用 Release，读取用 Acquire
+    pub latest_frame_slot: AtomicU32,
+    /// 数据包槽位
+    pub packet_slots: [PacketSlot; SLOT_COUNT],
+    /// 生产者发布的最新数据包槽位下标
+    pub latest_packet_slot: AtomicU32,
 }
 
 /// 帧头信息
@@ -48,6 +88,8 @@ pub struct FrameHeader {
     pub insert_idr: u8,
     /// 像素格式 (0=RGBA, 1=NV12, 2=P010)
     pub pixel_format: u8,
+    /// 颜色矩阵 (0=BT.709, 1=BT.2020)，由驱动根据内容请求
+    pub color_matrix: u8,
     /// 行跨度 (stride)
     pub row_pitch: u32,
     /// 帧数据大小
@@ -64,14 +106,44 @@ pub struct FrameHeader {
 pub struct PacketHeader {
     /// 数据包大小
     pub size: u32,
-    /// 时间戳
+    /// 时间戳 (PTS)
     pub timestamp_ns: u64,
+    /// 解码时间戳 (DTS)，硬件编码器不像 libx264 那样自动推导
+    pub dts_ns: u64,
     /// 是否为 IDR 帧
     pub is_idr: u8,
     /// 填充对齐
+    _padding: [u8; 7],
+}
+
+/// 运行时控制参数头信息。驱动每次更新参数时递增 `generation`，编码器据此
+/// 检测变更，而不需要单独的事件/信号。`generation` 是原子的 seqlock 守卫——
+/// 驱动写完其余字段后最后以 Release 递增它，读者先后两次 Acquire 读取它，
+/// 两次读到的世代号不同则丢弃中间读取的其余字段并重试。这只能发现读取
+/// 期间驱动完成了一次更新；驱动在递增世代号之前写到一半的字段，两次读取
+/// 得到的仍是同一个世代号，不会被检测出来 (其余字段本身仍是普通整数，不是原子的)
+#[repr(C)]
+pub struct ControlHeader {
+    /// 单调递增的世代号，每次更新参数时由驱动递增
+    pub generation: AtomicU64,
+    /// 目标码率 (bps)，0 表示不更新
+    pub target_bitrate_bps: u64,
+    /// 目标帧率 (fps)，0 表示不更新
+    pub target_framerate: u32,
+    /// 强制下一帧编码为 IDR
+    pub force_idr: u8,
+    /// 填充对齐
     _padding: [u8; 3],
 }
 
+/// 编码器从 `ControlHeader` 解析出的运行时参数更新
+#[derive(Clone, Copy, Debug)]
+pub struct ControlUpdate {
+    pub target_bitrate_bps: u64,
+    pub target_framerate: u32,
+    pub force_idr: bool,
+}
+
 /// 帧数据 (反序列化后的)
 pub struct FrameData {
     pub width: u32,
@@ -79,6 +151,7 @@ pub struct FrameData {
     pub timestamp_ns: u64,
     pub insert_idr: bool,
     pub pixel_format: PixelFormat,
+    pub color_matrix: ColorMatrix,
     pub row_pitch: u32,
     pub data: Vec<u8>,
     pub shutdown: bool,
@@ -88,6 +161,7 @@ pub struct FrameData {
 pub struct PacketData {
     pub data: Vec<u8>,
     pub timestamp_ns: u64,
+    pub dts_ns: u64,
     pub is_idr: bool,
 }
 
@@ -109,6 +183,22 @@ impl From<u8> for PixelFormat {
     }
 }
 
+/// 目标颜色矩阵，驱动据此为 HDR 内容请求 BT.2020
+#[derive(Clone, Copy, Debug)]
+pub enum ColorMatrix {
+    Bt709,
+    Bt2020,
+}
+
+impl From<u8> for ColorMatrix {
+    fn from(v: u8) -> Self {
+        match v {
+            1 => ColorMatrix::Bt2020,
+            _ => ColorMatrix::Bt709,
+        }
+    }
+}
+
 /// IPC 管理器
 pub struct EncoderIpc {
     shared_memory: HANDLE,
@@ -118,6 +208,10 @@ pub struct EncoderIpc {
     encoder_ready_event: HANDLE,
     width: u32,
     height: u32,
+    /// 上一次观察到的 `control_header.generation`
+    last_control_generation: u64,
+    /// 本进程发布数据包槽位时使用的单调序号计数器
+    next_packet_sequence: u64,
 }
 
 impl EncoderIpc {
@@ -179,6 +273,8 @@ impl EncoderIpc {
                 encoder_ready_event,
                 width,
                 height,
+                last_control_generation: 0,
+                next_packet_sequence: 1,
             })
         }
     }
@@ -192,7 +288,9 @@ impl EncoderIpc {
         Ok(())
     }
 
-    /// 等待帧数据
+    /// 等待帧数据。驱动侧是三缓冲的生产者：写入空闲槽位后以 Release 语义
+    /// 发布 `latest_frame_slot`，这里在槽位不处于 Ready 或拷贝期间槽位被
+    /// 重新写入时重新读取最新槽位，不会阻塞等待驱动"让出"缓冲区
     pub fn wait_for_frame(&self) -> Result<FrameData> {
         unsafe {
             // 等待帧就绪事件
@@ -201,55 +299,155 @@ impl EncoderIpc {
                 bail!("Wait for frame failed");
             }
 
-            // 读取帧头
-            let header = (*self.shared_ptr).frame_header;
-            
-            // 复制帧数据
-            let data_size = header.data_size as usize;
-            let mut data = vec![0u8; data_size];
-            ptr::copy_nonoverlapping(
-                (*self.shared_ptr).frame_buffer.as_ptr(),
-                data.as_mut_ptr(),
-                data_size,
-            );
+            loop {
+                let slot_index =
+                    (*self.shared_ptr).latest_frame_slot.load(Ordering::Acquire) as usize;
+                let slot = &(*self.shared_ptr).frame_slots[slot_index];
 
-            Ok(FrameData {
-                width: header.width,
-                height: header.height,
-                timestamp_ns: header.timestamp_ns,
-                insert_idr: header.insert_idr != 0,
-                pixel_format: header.pixel_format.into(),
-                row_pitch: header.row_pitch,
-                data,
-                shutdown: header.shutdown != 0,
-            })
+                // 把槽位占为 Reading，这样生产者挑选空闲槽位时会跳过它；如果
+                // 槽位不处于 Ready (生产者正赶上发布这一份)，重新读取最新槽位
+                if slot
+                    .state
+                    .compare_exchange(
+                        slot_state::READY,
+                        slot_state::READING,
+                        Ordering::Acquire,
+                        Ordering::Relaxed,
+                    )
+                    .is_err()
+                {
+                    continue;
+                }
+
+                let sequence_before = slot.sequence.load(Ordering::Acquire);
+                let header = slot.header;
+
+                let data_size = header.data_size as usize;
+                let mut data = vec![0u8; data_size];
+                ptr::copy_nonoverlapping(slot.data.as_ptr(), data.as_mut_ptr(), data_size);
+
+                // ARM64 (本机器目标平台) 的弱内存模型允许上面的拷贝被重排到
+                // 下面这次 sequence 读取之后，显式 Acquire 栅栏阻止这种重排——
+                // 标准 seqlock 读者写法，缺了它序号复核就可能放过一次撕裂读
+                fence(Ordering::Acquire);
+
+                // 序号变化说明生产者在我们拷贝期间复用了这个槽位，数据可能已经
+                // 被部分覆盖，丢弃本次结果，重新读取最新槽位
+                let sequence_after = slot.sequence.load(Ordering::Acquire);
+                slot.state.store(slot_state::READY, Ordering::Release);
+                if sequence_after != sequence_before {
+                    continue;
+                }
+
+                return Ok(FrameData {
+                    width: header.width,
+                    height: header.height,
+                    timestamp_ns: header.timestamp_ns,
+                    insert_idr: header.insert_idr != 0,
+                    pixel_format: header.pixel_format.into(),
+                    color_matrix: header.color_matrix.into(),
+                    row_pitch: header.row_pitch,
+                    data,
+                    shutdown: header.shutdown != 0,
+                });
+            }
+        }
+    }
+
+    /// 检测驱动是否发布了新的运行时控制参数 (码率/帧率/强制 IDR)。
+    /// 通过比较 `generation` 世代号实现，不需要轮询专用事件
+    pub fn poll_control(&mut self) -> Option<ControlUpdate> {
+        unsafe {
+            let control = &(*self.shared_ptr).control_header;
+
+            loop {
+                let generation_before = control.generation.load(Ordering::Acquire);
+                if generation_before == self.last_control_generation {
+                    return None;
+                }
+
+                let target_bitrate_bps = control.target_bitrate_bps;
+                let target_framerate = control.target_framerate;
+                let force_idr = control.force_idr;
+
+                // 防止上面这几次普通读取被重排到下面的世代号复核之后
+                fence(Ordering::Acquire);
+
+                // 世代号在我们读取参数期间又变了，说明读到了驱动写了一半的
+                // 数据，丢弃本次结果重新读取，不把半新半旧的参数组合喂给编码器
+                let generation_after = control.generation.load(Ordering::Acquire);
+                if generation_after != generation_before {
+                    continue;
+                }
+
+                self.last_control_generation = generation_after;
+                return Some(ControlUpdate {
+                    target_bitrate_bps,
+                    target_framerate,
+                    force_idr: force_idr != 0,
+                });
+            }
         }
     }
 
-    /// 发送编码后的数据包
+    /// 发送编码后的数据包。编码器是这条通路的生产者：在三个槽位里挑一个
+    /// 序号最旧的写入，从不等待驱动把上一份数据包取走，因此编码耗时不会
+    /// 反向拖慢这里的发送
     pub fn send_packet(&mut self, packet: &PacketData) -> Result<()> {
         unsafe {
-            // 写入数据包头
-            (*self.shared_ptr).packet_header = PacketHeader {
+            let slot_index = Self::claim_oldest_packet_slot(&*self.shared_ptr);
+            let slot = &mut (*self.shared_ptr).packet_slots[slot_index];
+
+            slot.state.store(slot_state::WRITING, Ordering::Relaxed);
+
+            slot.header = PacketHeader {
                 size: packet.data.len() as u32,
                 timestamp_ns: packet.timestamp_ns,
+                dts_ns: packet.dts_ns,
                 is_idr: if packet.is_idr { 1 } else { 0 },
-                _padding: [0; 3],
+                _padding: [0; 7],
             };
-
-            // 复制数据包数据
             ptr::copy_nonoverlapping(
                 packet.data.as_ptr(),
-                (*self.shared_ptr).packet_buffer.as_mut_ptr(),
+                slot.data.as_mut_ptr(),
                 packet.data.len(),
             );
 
-            // 通知数据包就绪
+            let sequence = self.next_packet_sequence;
+            self.next_packet_sequence += 1;
+            slot.sequence.store(sequence, Ordering::Release);
+            slot.state.store(slot_state::READY, Ordering::Release);
+
+            // 发布新的最新槽位下标，再通知驱动有数据包可读
+            (*self.shared_ptr)
+                .latest_packet_slot
+                .store(slot_index as u32, Ordering::Release);
             SetEvent(self.packet_ready_event)
                 .context("Failed to signal packet ready")?;
         }
         Ok(())
     }
+
+    /// 在 `SLOT_COUNT` 个数据包槽位中挑选序号最旧 (最早写入过/从未写入过) 的
+    /// 一个用于本次发布，跳过驱动正在读取 (Reading) 的槽位，避免覆盖它正在
+    /// 拷贝的数据
+    unsafe fn claim_oldest_packet_slot(layout: &SharedMemoryLayout) -> usize {
+        let mut oldest_index = None;
+        let mut oldest_sequence = u64::MAX;
+        for (index, slot) in layout.packet_slots.iter().enumerate() {
+            if slot.state.load(Ordering::Acquire) == slot_state::READING {
+                continue;
+            }
+            let sequence = slot.sequence.load(Ordering::Acquire);
+            if sequence < oldest_sequence {
+                oldest_sequence = sequence;
+                oldest_index = Some(index);
+            }
+        }
+        // 极端情况下三个槽位都在被读取；宁可冒一次覆盖正在读的槽位的风险，
+        // 也不能阻塞编码器主循环等待驱动读完
+        oldest_index.unwrap_or(0)
+    }
 }
 
 impl Drop for EncoderIpc {
@@ -5,6 +5,7 @@
 
 mod encoder;
 mod ipc;
+mod recorder;
 
 use anyhow::{Context, Result};
 use ipc::{EncoderIpc, FrameData, PacketData};
@@ -33,17 +34,24 @@ fn main() -> Result<()> {
     let width: u32 = args.get(1).and_then(|s| s.parse().ok()).unwrap_or(1920);
     let height: u32 = args.get(2).and_then(|s| s.parse().ok()).unwrap_or(1080);
     let codec: &str = args.get(3).map(|s| s.as_str()).unwrap_or("h264");
-    
+
+    // 可选的调试录制：设置 ALVR_ENCODER_RECORD_PATH 后，编码器实际产出的
+    // 数据包会额外写入这个 MP4/MKV 文件，不设置时不产生任何开销
+    let record_path = std::env::var("ALVR_ENCODER_RECORD_PATH").ok();
+    if let Some(path) = &record_path {
+        info!("Recording enabled: {}", path);
+    }
+
     info!("Encoder config: {}x{}, codec: {}", width, height, codec);
-    
+
     // 初始化 IPC
     let mut ipc = EncoderIpc::new(width, height)
         .context("Failed to initialize IPC")?;
-    
+
     info!("IPC initialized, waiting for frames...");
-    
+
     // 初始化编码器
-    let mut video_encoder = encoder::VideoEncoder::new(width, height, codec)
+    let mut video_encoder = encoder::VideoEncoder::new(width, height, codec, record_path)
         .context("Failed to initialize video encoder")?;
     
     info!("Video encoder initialized");
@@ -53,6 +61,12 @@ fn main() -> Result<()> {
     
     // 主循环
     loop {
+        // 检查驱动是否下发了新的运行时参数 (码率/帧率/强制 IDR)
+        if let Some(update) = ipc.poll_control() {
+            info!("Applying control channel update: {:?}", update);
+            video_encoder.apply_control_update(&update);
+        }
+
         // 等待帧数据
         match ipc.wait_for_frame() {
             Ok(frame_data) => {
@@ -0,0 +1,122 @@
+//! 可选的编码流录制模块 - 通过 libavformat 把编码器实际产出的数据包
+//! 写入 MP4/MKV 文件，用于离线排查延迟/画质问题
+
+use anyhow::{bail, Result};
+use std::ptr;
+use tracing::{info, warn};
+
+use crate::encoder::ffi;
+
+/// 将编码后的数据包录制到磁盘的 AVFormatContext 封装
+pub struct StreamRecorder {
+    fmt_ctx: *mut ffi::AVFormatContext,
+    stream_index: i32,
+    stream_time_base: ffi::AVRational,
+    codec_time_base: ffi::AVRational,
+}
+
+impl StreamRecorder {
+    /// 打开 `path` 作为录制输出 (由扩展名推断 MP4/Matroska 等格式)，并根据
+    /// 已经打开的编码器上下文创建匹配的视频流
+    pub fn new(path: &str, codec_ctx: *const ffi::AVCodecContext) -> Result<Self> {
+        unsafe {
+            let c_path = std::ffi::CString::new(path).unwrap();
+
+            let mut fmt_ctx: *mut ffi::AVFormatContext = ptr::null_mut();
+            let ret = ffi::avformat_alloc_output_context2(
+                &mut fmt_ctx,
+                ptr::null_mut(),
+                ptr::null(),
+                c_path.as_ptr(),
+            );
+            if ret < 0 || fmt_ctx.is_null() {
+                bail!("Could not allocate output context for '{}': error {}", path, ret);
+            }
+
+            let stream = ffi::avformat_new_stream(fmt_ctx, ptr::null());
+            if stream.is_null() {
+                ffi::avformat_free_context(fmt_ctx);
+                bail!("Could not create output stream for '{}'", path);
+            }
+
+            let ret = ffi::avcodec_parameters_from_context((*stream).codecpar, codec_ctx);
+            if ret < 0 {
+                ffi::avformat_free_context(fmt_ctx);
+                bail!("Could not copy codec parameters to stream: error {}", ret);
+            }
+            (*stream).time_base = (*codec_ctx).time_base;
+
+            if (*(*fmt_ctx).oformat).flags & ffi::AVFMT_NOFILE as i32 == 0 {
+                let ret = ffi::avio_open(&mut (*fmt_ctx).pb, c_path.as_ptr(), ffi::AVIO_FLAG_WRITE as i32);
+                if ret < 0 {
+                    ffi::avformat_free_context(fmt_ctx);
+                    bail!("Could not open recording output '{}': error {}", path, ret);
+                }
+            }
+
+            let ret = ffi::avformat_write_header(fmt_ctx, ptr::null_mut());
+            if ret < 0 {
+                ffi::avformat_free_context(fmt_ctx);
+                bail!("Could not write header for recording '{}': error {}", path, ret);
+            }
+
+            info!("Recording encoded stream to '{}'", path);
+
+            Ok(Self {
+                fmt_ctx,
+                stream_index: (*stream).index,
+                stream_time_base: (*stream).time_base,
+                codec_time_base: (*codec_ctx).time_base,
+            })
+        }
+    }
+
+    /// 写入一份刚从编码器取出的数据包的拷贝；传入的 `packet` 本身不受影响，
+    /// 仍然会正常交给共享内存路径发送给 ALVR 驱动
+    pub fn write_packet(&mut self, packet: *const ffi::AVPacket) {
+        unsafe {
+            let mut packet_copy = ffi::av_packet_clone(packet);
+            if packet_copy.is_null() {
+                warn!("Could not clone packet for recording");
+                return;
+            }
+
+            (*packet_copy).stream_index = self.stream_index;
+
+            // MF/AMF 等硬件编码器常常不推导 DTS，给的是 AV_NOPTS_VALUE 或负值
+            // (encoder.rs 里 `PacketData.dts_ns` 的同一个问题)；
+            // av_interleaved_write_frame 会拒绝非单调/NOPTS 的 dts，整份录制
+            // 文件就会每包告警、写不出东西，这里退回 PTS 再参与时间基换算
+            let dts = (*packet_copy).dts;
+            if dts == ffi::AV_NOPTS_VALUE || dts < 0 {
+                (*packet_copy).dts = (*packet_copy).pts;
+            }
+            ffi::av_packet_rescale_ts(packet_copy, self.codec_time_base, self.stream_time_base);
+
+            let ret = ffi::av_interleaved_write_frame(self.fmt_ctx, packet_copy);
+            if ret < 0 {
+                warn!("Failed to write packet to recording: error {}", ret);
+            }
+
+            ffi::av_packet_free(&mut packet_copy);
+        }
+    }
+}
+
+impl Drop for StreamRecorder {
+    fn drop(&mut self) {
+        unsafe {
+            if self.fmt_ctx.is_null() {
+                return;
+            }
+
+            ffi::av_write_trailer(self.fmt_ctx);
+
+            if (*(*self.fmt_ctx).oformat).flags & ffi::AVFMT_NOFILE as i32 == 0 {
+                ffi::avio_closep(&mut (*self.fmt_ctx).pb);
+            }
+
+            ffi::avformat_free_context(self.fmt_ctx);
+        }
+    }
+}